@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+const MAX_APPENDED_SILENCE_MS: u32 = 5000;
+
+/// Settings read from the module config file, in speech-dispatcher's dotconf format
+#[derive(Default)]
+pub(crate) struct Config {
+    pub(crate) appended_silence_ms: u32,
+    pub(crate) default_voice: Option<String>,
+}
+
+impl Config {
+    pub(crate) fn from_path(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path).context("Failed to read module config")?;
+        let mut config = Config::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .map_or((line, ""), |(key, value)| (key, value.trim()));
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+
+            match key {
+                "PiperAppendedSilenceMs" => {
+                    if let Some(ms) = parse_appended_silence_ms(value) {
+                        config.appended_silence_ms = ms;
+                    }
+                }
+                "PiperDefaultVoice" => config.default_voice = Some(value.to_string()),
+                // the config may contain settings meant for speech-dispatcher itself
+                _ => debug!("Ignoring unknown config option {key:?}"),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parses a duration of silence to append to messages, ignoring it if it's invalid
+pub(crate) fn parse_appended_silence_ms(value: &str) -> Option<u32> {
+    match value.parse::<u64>() {
+        // clamp absurd values rather than rejecting them
+        Ok(ms) => Some(ms.min(MAX_APPENDED_SILENCE_MS.into()) as u32),
+        Err(e) => {
+            warn!("Ignoring invalid appended silence {value:?}: {e}");
+            None
+        }
+    }
+}
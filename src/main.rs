@@ -1,11 +1,14 @@
 #![feature(unix_mkfifo, path_file_prefix, map_try_insert, if_let_guard)]
 
 use std::collections::HashMap;
+use std::env;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{Write, stdout};
+use std::fs::{File, Permissions};
+use std::io::{ErrorKind, Write, stdout};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt, PermissionsExt, mkfifo};
 use std::panic;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result, anyhow, bail};
 use log::{Level, Log, debug, error, info, trace, warn};
@@ -15,7 +18,51 @@ use piper_rs::{ModelConfig, PiperError};
 use serde_ssml::SsmlElement;
 use xdg::BaseDirectories;
 
+use crate::config::Config;
+
+mod config;
 mod io;
+mod sentences;
+
+const NORMAL_PITCH: f32 = 1.0;
+const MIN_PITCH: f32 = 0.5;
+const MAX_PITCH: f32 = 2.0;
+
+const NORMAL_RATE: f32 = 1.0;
+const MIN_RATE: f32 = 0.5;
+const MAX_RATE: f32 = 4.5;
+
+const NORMAL_VOLUME: f32 = 1.0;
+const MIN_VOLUME: f32 = 0.0;
+/// Emphasis can go louder than normal, which is the loudest volume a client can set
+const MAX_EMPHASIS_VOLUME: f32 = 1.5;
+
+struct Voice {
+    path: PathBuf,
+    config: ModelConfig,
+    synth: Option<PiperSpeechSynthesizer>,
+}
+
+/// Settings for synthesis, passed down through nested elements
+#[derive(Clone, Copy)]
+struct Prosody {
+    pitch: f32,
+    rate: f32,
+    volume: f32,
+    appended_silence_ms: u32,
+}
+
+enum AudioOutput {
+    /// Send audio inline to the server with `705-AUDIO`
+    Server,
+    /// Write raw PCM to a fifo, which is opened the first time there's audio to write
+    ///
+    /// The path comes from `audio_retrieval_path`, which is specific to this module rather than
+    /// a standard speech-dispatcher setting. There's no way to tell the reader the format, so it
+    /// has to know it ahead of time, and switching to a voice with a different sample rate isn't
+    /// allowed.
+    Retrieval { path: PathBuf, fifo: Option<File> },
+}
 
 fn main() -> Result<()> {
     if let Err(e) = start() {
@@ -69,6 +116,15 @@ fn start() -> Result<()> {
 
     send!("299-Everything ok so far");
 
+    // speech-dispatcher passes the path to the module config as the first argument
+    let config = match env::args_os().nth(1) {
+        Some(path) => Config::from_path(path.as_ref()).unwrap_or_else(|e| {
+            warn!("Failed to load module config {path:?}: {e:?}");
+            Config::default()
+        }),
+        None => Config::default(),
+    };
+
     let Some(voice_dir) = BaseDirectories::new()
         .get_data_home()
         .map(|dir| dir.join("piper-voices"))
@@ -76,8 +132,8 @@ fn start() -> Result<()> {
         bail!("Failed to resolve voice directory. XDG_DATA_HOME and HOME are unset");
     };
 
-    let mut voices: HashMap<String, (PathBuf, Option<PiperSpeechSynthesizer>)> = {
-        voice_dir
+    let mut voices: HashMap<String, Voice> = {
+        let mut found = voice_dir
             .read_dir()
             .context("Failed to enumerate voices")?
             .filter_map(|entry| {
@@ -113,30 +169,44 @@ fn start() -> Result<()> {
                 };
 
                 // SAFETY: safe because we matched on having a name ending in .json
-                let mut name = path.file_prefix().unwrap().to_string_lossy().to_string();
-
-                // strip the lang prefix from the name if there is one
-                if name
-                    .to_lowercase()
-                    .replace('_', "-")
-                    .strip_prefix(&config.espeak.voice)
-                    .is_some_and(|name| name.starts_with(['-', '_']))
-                {
-                    name = name[config.espeak.voice.len() + 1..].to_string()
-                }
-
-                Some((name, (path.clone(), None)))
+                let name = voice_name(
+                    &path.file_prefix().unwrap().to_string_lossy(),
+                    &config.espeak.voice,
+                    config.audio.quality.as_deref(),
+                );
+
+                Some((
+                    name,
+                    Voice {
+                        path,
+                        config,
+                        synth: None,
+                    },
+                ))
             })
-            .collect()
+            .collect::<Vec<_>>();
+
+        // sort so names are assigned consistently between launches
+        found.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path));
+
+        assign_names(
+            found,
+            |voice| &voice.config.espeak.voice,
+            |voice| &voice.path,
+        )
     };
 
-    let Some(mut voice) = voices.keys().next().map(String::to_string) else {
+    let Some(mut voice) = default_voice(&voices, config.default_voice.as_deref()) else {
         bail!("No models available");
     };
 
-    let mut pitch = 1.0;
-    let mut rate = 1.0;
-    let mut volume = 1.0;
+    let mut prosody = Prosody {
+        pitch: NORMAL_PITCH,
+        rate: NORMAL_RATE,
+        volume: NORMAL_VOLUME,
+        appended_silence_ms: config.appended_silence_ms,
+    };
+    let mut audio_output = AudioOutput::Server;
 
     send!("299 OK LOADED SUCCESSFULLY");
 
@@ -144,10 +214,41 @@ fn start() -> Result<()> {
         match recv!().as_str() {
             "AUDIO" => {
                 send!("207 OK RECEIVING AUDIO SETTINGS");
-                if recv!() != "audio_output_method=server" || recv!() != "." {
-                    bail!("Audio output method must be server!");
+                let mut method = None;
+                let mut retrieval_path = None;
+                loop {
+                    match recv!().as_str() {
+                        "." => break,
+                        line if let Some((key, value)) = line.split_once('=') => match key {
+                            "audio_output_method" => method = Some(value.to_string()),
+                            "audio_retrieval_path" => retrieval_path = Some(PathBuf::from(value)),
+                            _ => warn!("Ignoring unknown setting {key:?}"),
+                        },
+                        _ => {
+                            bail!("Malformed setting");
+                        }
+                    }
+                }
+
+                let output = match method.as_deref() {
+                    Some("server") => Ok(AudioOutput::Server),
+                    Some("retrieval") => retrieval_output(retrieval_path),
+                    _ => Err(anyhow!("Audio output method must be server or retrieval")),
+                };
+                match output {
+                    Ok(output) => {
+                        audio_output = output;
+                        send!("203 OK AUDIO INITIALIZED");
+                    }
+                    Err(e) => {
+                        error!("{e:?}");
+                        audio_output = AudioOutput::Server;
+                        for e in e.chain() {
+                            send!("300-{e}");
+                        }
+                        send!("300 ERR AUDIO NOT INITIALIZED");
+                    }
                 }
-                send!("203 OK AUDIO INITIALIZED");
             }
 
             "LOGLEVEL" => {
@@ -186,22 +287,7 @@ fn start() -> Result<()> {
             }
 
             "LIST VOICES" => {
-                for (name, (path, _)) in &voices {
-                    let file = match File::open(path) {
-                        Ok(file) => file,
-                        Err(e) => {
-                            warn!("Failed to open model config {path:?}: {e:?}");
-                            continue;
-                        }
-                    };
-                    let config: ModelConfig = match serde_json::from_reader(file) {
-                        Ok(config) => config,
-                        Err(e) => {
-                            warn!("Failed to parse model config: {path:?}: {e:?}");
-                            continue;
-                        }
-                    };
-
+                for (name, Voice { path, config, .. }) in &voices {
                     // intentionally make it so firefox doesn't recognize the format
                     // otherwise it'll hide the name, which isn't what we want
                     let lang = {
@@ -214,7 +300,7 @@ fn start() -> Result<()> {
 
                     send!(
                         "200-{name}\t{lang}\t{}",
-                        config.dataset.unwrap_or("none".to_string())
+                        config.dataset.as_deref().unwrap_or("none")
                     );
                 }
                 send!("200 OK VOICE LIST SENT");
@@ -235,48 +321,45 @@ fn start() -> Result<()> {
                     };
                     match name {
                         "pitch" => {
-                            const NORMAL_PITCH: f32 = 1.0;
-                            const MIN_PITCH: f32 = 0.5;
-                            const MAX_PITCH: f32 = 2.0;
-
-                            pitch =
-                                value.parse::<f32>().context("Invalid value for pitch")? / 100.0;
-
-                            if pitch < 0.0 {
-                                pitch = NORMAL_PITCH + (NORMAL_PITCH - MIN_PITCH) * pitch;
-                            } else {
-                                pitch = NORMAL_PITCH + (MAX_PITCH - NORMAL_PITCH) * pitch;
+                            if let Some(value) = parse_percent(name, value) {
+                                prosody.pitch = scale(value, MIN_PITCH, NORMAL_PITCH, MAX_PITCH);
                             }
                         }
 
-                        // adapted from https://github.com/brailcom/speechd/blob/ffbbec5aa1b53cca96b2dbb42c54d520ef1cf098/src/modules/espeak.c#L416
                         "rate" => {
-                            const NORMAL_RATE: f32 = 1.0;
-                            const MIN_RATE: f32 = 0.5;
-                            const MAX_RATE: f32 = 4.5;
-
-                            rate = value.parse::<f32>().context("Invalid value for rate")? / 100.0;
-
-                            if rate < 0.0 {
-                                rate = NORMAL_RATE + (NORMAL_RATE - MIN_RATE) * rate;
-                            } else {
-                                rate = NORMAL_RATE + (MAX_RATE - NORMAL_RATE) * rate;
+                            if let Some(value) = parse_percent(name, value) {
+                                prosody.rate = scale(value, MIN_RATE, NORMAL_RATE, MAX_RATE);
                             }
                         }
 
+                        // 100 is normal and anything at or below 0 is silent
                         "volume" => {
-                            volume =
-                                value.parse::<f32>().context("Invalid value for volume")? / 100.0;
+                            if let Some(value) = parse_percent(name, value) {
+                                prosody.volume = value.clamp(MIN_VOLUME, NORMAL_VOLUME);
+                            }
                         }
 
-                        "synthesis_voice" => {
-                            if voices.contains_key(value) {
-                                voice = value.to_string();
-                            } else {
-                                warn!("Not setting voice to unknown {value:?}");
+                        "appended_silence_ms" => {
+                            if let Some(ms) = config::parse_appended_silence_ms(value) {
+                                prosody.appended_silence_ms = ms;
                             }
                         }
 
+                        "synthesis_voice" => match voices.get(value) {
+                            Some(new_voice)
+                                if matches!(audio_output, AudioOutput::Retrieval { .. })
+                                    && new_voice.config.audio.sample_rate
+                                        != voices[&voice].config.audio.sample_rate =>
+                            {
+                                warn!(
+                                    "Not setting voice to {value:?} since its sample rate differs \
+                                    and the audio fifo can't be told"
+                                );
+                            }
+                            Some(_) => voice = value.to_string(),
+                            None => warn!("Not setting voice to unknown {value:?}"),
+                        },
+
                         _ => (),
                     }
                 }
@@ -305,7 +388,13 @@ fn start() -> Result<()> {
                 debug!("Parsed SSML: {ssml:#?}");
                 send!("200 OK SPEAKING");
                 send!("701 BEGIN");
-                match speak(&ssml.elements, &mut voices, &voice, pitch, rate, volume) {
+                match speak(
+                    &ssml.elements,
+                    &mut voices,
+                    &voice,
+                    prosody,
+                    &mut audio_output,
+                ) {
                     Ok(StopCondition::End | StopCondition::Pause { .. }) => {
                         send!("702 END");
                     }
@@ -339,102 +428,390 @@ fn start() -> Result<()> {
     }
 }
 
+/// Checks the path for the retrieval audio output method, creating the fifo if it doesn't exist
+fn retrieval_output(path: Option<PathBuf>) -> Result<AudioOutput> {
+    let Some(path) = path else {
+        bail!("Retrieval audio output method requires audio_retrieval_path");
+    };
+
+    match path.metadata() {
+        Ok(metadata) if metadata.file_type().is_fifo() => (),
+        Ok(_) => bail!("Audio retrieval path {path:?} is not a fifo"),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            mkfifo(&path, Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to create audio fifo {path:?}"))?;
+        }
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to check audio retrieval path {path:?}"));
+        }
+    }
+
+    Ok(AudioOutput::Retrieval { path, fifo: None })
+}
+
+/// Opens a fifo for writing, failing instead of blocking if there's no reader
+fn open_fifo(path: &Path) -> Result<File> {
+    let file = match File::options()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+            bail!("Nothing is reading from audio fifo {path:?}");
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to open audio fifo {path:?}")),
+    };
+
+    // writes should still block so audio isn't dropped when the fifo is full
+    let fd = file.as_raw_fd();
+    // SAFETY: safe because the fd is owned by file, which is still open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    // SAFETY: same as above
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } == -1 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to configure audio fifo {path:?}"));
+    }
+
+    Ok(file)
+}
+
+/// Parses a pitch, rate, or volume setting, ignoring it if it's invalid or out of range
+fn parse_percent(name: &str, value: &str) -> Option<f32> {
+    let value = match value.parse::<f32>() {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("Ignoring invalid value {value:?} for {name}: {e}");
+            return None;
+        }
+    };
+
+    if (-100.0..=100.0).contains(&value) {
+        Some(value / 100.0)
+    } else {
+        warn!("Ignoring out of range value {value} for {name}");
+        None
+    }
+}
+
+/// Maps a value in ±1 onto `min..=max`, with 0 mapping to `normal`
+// adapted from https://github.com/brailcom/speechd/blob/ffbbec5aa1b53cca96b2dbb42c54d520ef1cf098/src/modules/espeak.c#L416
+fn scale(value: f32, min: f32, normal: f32, max: f32) -> f32 {
+    let scaled = if value < 0.0 {
+        normal + (normal - min) * value
+    } else {
+        normal + (max - normal) * value
+    };
+    scaled.clamp(min, max)
+}
+
+/// Derives a voice name from its config file name, e.g. `en_US-ryan-high` becomes `ryan-high`
+fn voice_name(file_prefix: &str, espeak_voice: &str, quality: Option<&str>) -> String {
+    let mut name = file_prefix.to_string();
+
+    // strip the lang prefix from the name if there is one
+    if name
+        .to_lowercase()
+        .replace('_', "-")
+        .strip_prefix(espeak_voice)
+        .is_some_and(|name| name.starts_with(['-', '_']))
+    {
+        name = name[espeak_voice.len() + 1..].to_string()
+    }
+
+    // include the quality so different variants of the same voice can be told apart
+    if let Some(quality) = quality {
+        let lowercase = name.to_lowercase();
+        let quality = quality.to_lowercase();
+        if !lowercase.ends_with(&format!("-{quality}"))
+            && !lowercase.ends_with(&format!("_{quality}"))
+        {
+            name = format!("{name}-{quality}");
+        }
+    }
+
+    name
+}
+
+/// Gives each voice a unique name, qualifying taken names with the language, then with a number
+fn assign_names<T>(
+    found: Vec<(String, T)>,
+    lang: impl Fn(&T) -> &str,
+    path: impl Fn(&T) -> &Path,
+) -> HashMap<String, T> {
+    let mut voices = HashMap::new();
+    for (name, mut voice) in found {
+        let candidates = [name.clone(), format!("{name}-{}", lang(&voice))]
+            .into_iter()
+            .chain((2..).map(|n| format!("{name}-{n}")));
+        for candidate in candidates {
+            match voices.try_insert(candidate.clone(), voice) {
+                Ok(voice) => {
+                    if candidate != name {
+                        warn!(
+                            "Voice name {name:?} is already taken, using {candidate:?} for {:?}",
+                            path(voice)
+                        );
+                    }
+                    break;
+                }
+                Err(e) => voice = e.value,
+            }
+        }
+    }
+    voices
+}
+
+/// Picks the voice to use on startup
+///
+/// The voice from the module config takes precedence, then a voice matching the locale's language
+/// and region, then one matching just the language, then any English voice, then whatever's first.
+fn default_voice(voices: &HashMap<String, Voice>, config_voice: Option<&str>) -> Option<String> {
+    if let Some(name) = config_voice {
+        if voices.contains_key(name) {
+            return Some(name.to_string());
+        }
+        warn!("Default voice {name:?} from module config is not available");
+    }
+
+    // sort so the fallbacks are at least consistent between launches
+    let mut names = voices.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let find = |lang: &str, region: Option<&str>| {
+        names.iter().find(|name| {
+            let espeak_voice = voices[name.as_str()].config.espeak.voice.to_lowercase();
+            let (voice_lang, voice_region) = match espeak_voice.split_once('-') {
+                Some((lang, region)) => (lang, Some(region)),
+                None => (espeak_voice.as_str(), None),
+            };
+            voice_lang == lang && region.is_none_or(|region| voice_region == Some(region))
+        })
+    };
+
+    let name = locale()
+        .and_then(|(lang, region)| {
+            region
+                .and_then(|region| find(&lang, Some(&region)))
+                .or_else(|| find(&lang, None))
+        })
+        .or_else(|| find("en", None))
+        .or(names.first());
+
+    name.map(|name| name.to_string())
+}
+
+/// Returns the lowercased language and region of the user's locale, e.g. `("en", Some("us"))`
+fn locale() -> Option<(String, Option<String>)> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .find_map(|var| env::var(var).ok().filter(|locale| !locale.is_empty()))?;
+
+    // strip the codeset and modifier, e.g. en_US.UTF-8@euro
+    let locale = locale.split(['.', '@']).next()?.to_lowercase();
+    if locale == "c" || locale == "posix" {
+        return None;
+    }
+
+    match locale.split_once(['_', '-']) {
+        Some((lang, region)) => Some((lang.to_string(), Some(region.to_string()))),
+        None => Some((locale, None)),
+    }
+}
+
 enum StopCondition {
     End,
     Stop,
     Pause { handled: bool },
 }
 
+/// Checks whether an element contains any text to synthesize
+fn has_speech(element: &SsmlElement) -> bool {
+    match element {
+        SsmlElement::Text(text) => !text.trim().is_empty(),
+        SsmlElement::Sub {
+            alias: Some(alias), ..
+        } => !alias.trim().is_empty(),
+        SsmlElement::Speak { children, .. }
+        | SsmlElement::Emphasis { children, .. }
+        | SsmlElement::Sub { children, .. } => children.iter().any(has_speech),
+        _ => false,
+    }
+}
+
+/// Speaks the elements, appending the prosody's silence after the last of the speech
 fn speak(
     elements: &[SsmlElement],
-    voices: &mut HashMap<String, (PathBuf, Option<PiperSpeechSynthesizer>)>,
+    voices: &mut HashMap<String, Voice>,
     voice: &str,
-    pitch: f32,
-    rate: f32,
-    volume: f32,
+    prosody: Prosody,
+    audio_output: &mut AudioOutput,
 ) -> Result<StopCondition> {
     let mut should_pause = false;
 
-    for element in elements {
+    // only the last element that makes any sound gets the silence, so it's once per message
+    let last_speech = elements.iter().rposition(has_speech);
+
+    for (i, element) in elements.iter().enumerate() {
+        let prosody = if Some(i) == last_speech {
+            prosody
+        } else {
+            Prosody {
+                appended_silence_ms: 0,
+                ..prosody
+            }
+        };
+
         match element {
             SsmlElement::Speak { children, .. } => {
-                match speak(children, voices, voice, pitch, rate, volume)? {
+                match speak(children, voices, voice, prosody, audio_output)? {
                     StopCondition::End => (),
-                    StopCondition::Stop => return Ok(StopCondition::Stop),
-                    StopCondition::Pause { handled: true } => {
-                        return Ok(StopCondition::Pause { handled: true });
-                    }
-                    StopCondition::Pause { handled: false } => {
-                        should_pause = true;
+                    StopCondition::Pause { handled: false } => should_pause = true,
+                    condition => return Ok(condition),
+                }
+            }
+
+            SsmlElement::Emphasis { level, children } => {
+                // multipliers for pitch, rate, and volume
+                let (pitch_scale, rate_scale, volume_scale) = match level.as_deref() {
+                    Some("reduced") => (0.95, 1.1, 0.8),
+                    Some("none") => (1.0, 1.0, 1.0),
+                    // moderate is the default level according to the spec
+                    Some("moderate") | None => (1.05, 0.95, 1.1),
+                    Some("strong") => (1.1, 0.85, 1.2),
+                    Some(level) => {
+                        warn!("Ignoring unknown emphasis level {level:?}");
+                        (1.0, 1.0, 1.0)
                     }
+                };
+
+                let prosody = Prosody {
+                    pitch: (prosody.pitch * pitch_scale).clamp(MIN_PITCH, MAX_PITCH),
+                    rate: (prosody.rate * rate_scale).clamp(MIN_RATE, MAX_RATE),
+                    volume: (prosody.volume * volume_scale).clamp(MIN_VOLUME, MAX_EMPHASIS_VOLUME),
+                    ..prosody
+                };
+
+                match speak(children, voices, voice, prosody, audio_output)? {
+                    StopCondition::End => (),
+                    StopCondition::Pause { handled: false } => should_pause = true,
+                    condition => return Ok(condition),
+                }
+            }
+
+            SsmlElement::Sub { alias, children } => {
+                // the alias replaces the whole contents of the element, including any markup
+                let alias = alias
+                    .as_ref()
+                    .map(|alias| [SsmlElement::Text(alias.to_string())]);
+                let children = alias
+                    .as_ref()
+                    .map_or(children.as_slice(), |alias| alias.as_slice());
+
+                match speak(children, voices, voice, prosody, audio_output)? {
+                    StopCondition::End => (),
+                    StopCondition::Pause { handled: false } => should_pause = true,
+                    condition => return Ok(condition),
                 }
             }
 
             SsmlElement::Text(text) => {
-                let synth = match &voices[voice].1 {
+                let synth = match &voices[voice].synth {
                     Some(synth) => synth,
                     None => {
-                        let model = piper_rs::from_config_path(&voices[voice].0)
+                        let model = piper_rs::from_config_path(&voices[voice].path)
                             .context("Failed to parse model config")?;
                         let synth = PiperSpeechSynthesizer::new(model)
                             .context("Failed to initialize model")?;
                         // SAFETY: safe as long as voice is a valid key to voices
                         // we only set it if it is a valid key, so it should be guaranteed to be
                         // also, if there aren't any voices, we already panicked
-                        voices.get_mut(voice).unwrap().1.insert(synth)
+                        voices.get_mut(voice).unwrap().synth.insert(synth)
                     }
                 };
 
                 let model = synth.clone_model();
                 let output_info = model.audio_output_info();
 
-                let output_config = Some(AudioOutputConfig {
-                    rate: Some(rate),
-                    volume: Some(volume),
-                    pitch: Some(pitch),
-                    appended_silence_ms: None,
-                });
-
-                let output: &mut dyn Iterator<Item = Result<Vec<u8>, PiperError>> = if model
-                    .supports_streaming_output()
-                {
-                    &mut synth
-                        .synthesize_streamed(text.to_string(), output_config, 1, 1)?
-                        .map(|audio| Ok(audio?.as_wave_bytes()))
-                } else {
-                    &mut synth
-                        .synthesize_parallel(text.to_string(), output_config)?
-                        .map(|audio| -> Result<Vec<u8>, PiperError> { Ok(audio?.as_wave_bytes()) })
-                };
-                for audio in output {
-                    // handle interrupts
-                    if let Some(line) = try_recv!() {
-                        match line.as_str() {
-                            "STOP" => return Ok(StopCondition::Stop),
-                            "PAUSE" => should_pause = true,
-                            cmd => bail!("Unexpected command during playback: {cmd:?}"),
+                // synthesize a sentence at a time so audio starts playing sooner
+                let sentences = sentences::split(text);
+                for (n, sentence) in sentences.iter().enumerate() {
+                    let output_config = Some(AudioOutputConfig {
+                        rate: Some(prosody.rate),
+                        volume: Some(prosody.volume),
+                        pitch: Some(prosody.pitch),
+                        // only append silence after the last sentence
+                        // 0 means no silence, which is the same as not setting it
+                        appended_silence_ms: Some(prosody.appended_silence_ms)
+                            .filter(|&ms| ms > 0 && n == sentences.len() - 1),
+                    });
+
+                    let output: &mut dyn Iterator<Item = Result<Vec<u8>, PiperError>> =
+                        if model.supports_streaming_output() {
+                            &mut synth
+                                .synthesize_streamed(sentence.to_string(), output_config, 1, 1)?
+                                .map(|audio| Ok(audio?.as_wave_bytes()))
+                        } else {
+                            &mut synth
+                                .synthesize_parallel(sentence.to_string(), output_config)?
+                                .map(|audio| -> Result<Vec<u8>, PiperError> {
+                                    Ok(audio?.as_wave_bytes())
+                                })
+                        };
+                    for audio in output {
+                        // handle interrupts
+                        if let Some(line) = try_recv!() {
+                            match line.as_str() {
+                                "STOP" => return Ok(StopCondition::Stop),
+                                "PAUSE" => should_pause = true,
+                                cmd => bail!("Unexpected command during playback: {cmd:?}"),
+                            }
                         }
-                    }
 
-                    let mut audio = audio?;
-
-                    send!("705-bits={}", output_info.sample_width * 8);
-                    send!("705-num_channels={}", output_info.num_channels);
-                    send!("705-sample_rate={}", output_info.sample_rate);
-                    send!("705-num_samples={}", audio.len() / output_info.sample_width);
+                        let mut audio = audio?;
+
+                        match audio_output {
+                            AudioOutput::Server => {
+                                send!("705-bits={}", output_info.sample_width * 8);
+                                send!("705-num_channels={}", output_info.num_channels);
+                                send!("705-sample_rate={}", output_info.sample_rate);
+                                send!("705-num_samples={}", audio.len() / output_info.sample_width);
+
+                                for i in (0..audio.len()).rev() {
+                                    if audio[i] == b'\n' || audio[i] == 0x7d {
+                                        audio[i] ^= 1 << 5;
+                                        audio.insert(i, 0x7d);
+                                    }
+                                }
+
+                                print!("705-AUDIO\0");
+                                stdout().write_all(&audio)?;
+                                send!();
+                                trace!("< 705-AUDIO<raw audio bytes...>");
+                                send!("705 AUDIO");
+                            }
 
-                    for i in (0..audio.len()).rev() {
-                        if audio[i] == b'\n' || audio[i] == 0x7d {
-                            audio[i] ^= 1 << 5;
-                            audio.insert(i, 0x7d);
+                            AudioOutput::Retrieval { path, fifo } => {
+                                if fifo.is_none() {
+                                    info!(
+                                        "Writing {}-bit {} channel audio at {} Hz to {path:?}",
+                                        output_info.sample_width * 8,
+                                        output_info.num_channels,
+                                        output_info.sample_rate
+                                    );
+                                    *fifo = Some(open_fifo(path)?);
+                                }
+
+                                // SAFETY: safe because we just made sure it's set
+                                if let Err(e) = fifo.as_mut().unwrap().write_all(&audio) {
+                                    // the reader probably went away, so reopen it next time
+                                    *fifo = None;
+                                    return Err(e).context("Failed to write to audio fifo");
+                                }
+                            }
                         }
                     }
-
-                    print!("705-AUDIO\0");
-                    stdout().write_all(&audio)?;
-                    send!();
-                    trace!("< 705-AUDIO<raw audio bytes...>");
-                    send!("705 AUDIO");
                 }
             }
 
@@ -456,3 +833,117 @@ fn speak(
         Ok(StopCondition::End)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_percent_in_range() {
+        assert_eq!(parse_percent("pitch", "-100"), Some(-1.0));
+        assert_eq!(parse_percent("pitch", "0"), Some(0.0));
+        assert_eq!(parse_percent("pitch", "100"), Some(1.0));
+    }
+
+    #[test]
+    fn parse_percent_out_of_range() {
+        for value in ["-101", "101", "100000", "NaN", "inf", "-inf"] {
+            assert_eq!(parse_percent("pitch", value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn parse_percent_invalid() {
+        assert_eq!(parse_percent("volume", "loud"), None);
+        assert_eq!(parse_percent("volume", ""), None);
+    }
+
+    #[test]
+    fn scale_bounds() {
+        for (min, normal, max) in [
+            (MIN_PITCH, NORMAL_PITCH, MAX_PITCH),
+            (MIN_RATE, NORMAL_RATE, MAX_RATE),
+        ] {
+            assert_eq!(scale(-1.0, min, normal, max), min);
+            assert_eq!(scale(0.0, min, normal, max), normal);
+            assert_eq!(scale(1.0, min, normal, max), max);
+        }
+    }
+
+    #[test]
+    fn scale_clamps() {
+        for (min, normal, max) in [
+            (MIN_PITCH, NORMAL_PITCH, MAX_PITCH),
+            (MIN_RATE, NORMAL_RATE, MAX_RATE),
+        ] {
+            assert_eq!(scale(-5.0, min, normal, max), min);
+            assert_eq!(scale(5.0, min, normal, max), max);
+        }
+    }
+
+    #[test]
+    fn voice_name_strips_lang() {
+        assert_eq!(voice_name("en_US-ryan-high", "en-us", None), "ryan-high");
+        assert_eq!(voice_name("ryan-high", "en-us", None), "ryan-high");
+    }
+
+    #[test]
+    fn voice_name_adds_quality() {
+        assert_eq!(
+            voice_name("en_US-ryan-low", "en-us", Some("low")),
+            "ryan-low"
+        );
+        assert_eq!(voice_name("en_US-ryan", "en-us", Some("high")), "ryan-high");
+        assert_eq!(
+            voice_name("en_US-amy-x_low", "en-us", Some("x_low")),
+            "amy-x_low"
+        );
+        assert_eq!(voice_name("marlow", "en-us", Some("low")), "marlow-low");
+    }
+
+    #[test]
+    fn assign_names_keeps_qualities_apart() {
+        let voices = assign_names(
+            vec![
+                (
+                    voice_name("en_US-ryan-low", "en-us", Some("low")),
+                    ("en-us", "en_US-ryan-low.onnx.json"),
+                ),
+                (
+                    voice_name("en_US-ryan-high", "en-us", Some("high")),
+                    ("en-us", "en_US-ryan-high.onnx.json"),
+                ),
+            ],
+            |(lang, _)| lang,
+            |(_, path)| Path::new(path),
+        );
+        assert_eq!(voices.len(), 2);
+        assert_eq!(voices["ryan-low"].1, "en_US-ryan-low.onnx.json");
+        assert_eq!(voices["ryan-high"].1, "en_US-ryan-high.onnx.json");
+    }
+
+    #[test]
+    fn assign_names_resolves_collisions() {
+        let voices = assign_names(
+            vec![
+                (
+                    voice_name("en_US-ryan-high", "en-us", Some("high")),
+                    ("en-us", "en_US-ryan-high.onnx.json"),
+                ),
+                (
+                    voice_name("en_GB-ryan-high", "en-gb", Some("high")),
+                    ("en-gb", "en_GB-ryan-high.onnx.json"),
+                ),
+                (
+                    voice_name("ryan-high", "en-gb", Some("high")),
+                    ("en-gb", "ryan-high.onnx.json"),
+                ),
+            ],
+            |(lang, _)| lang,
+            |(_, path)| Path::new(path),
+        );
+        assert_eq!(voices["ryan-high"].1, "en_US-ryan-high.onnx.json");
+        assert_eq!(voices["ryan-high-en-gb"].1, "en_GB-ryan-high.onnx.json");
+        assert_eq!(voices["ryan-high-2"].1, "ryan-high.onnx.json");
+    }
+}
@@ -0,0 +1,166 @@
+/// Abbreviations that don't end a sentence, lowercased and without the final period
+const ABBREVIATIONS: &[&str] = &[
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "mt", "vs", "e.g", "i.e", "approx", "dept",
+];
+
+/// Abbreviations that only don't end a sentence when they're followed by a number, e.g. `No. 5`
+const NUMBERED_ABBREVIATIONS: &[&str] = &["no", "fig", "vol", "p", "pp"];
+
+/// Splits text into sentences, trimming whitespace and skipping empty ones
+///
+/// This is only a heuristic. Periods inside numbers, in runs of initials, and after common
+/// abbreviations don't end a sentence.
+pub(crate) fn split(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?' | '…') {
+            continue;
+        }
+
+        // include any following terminators and closing punctuation, e.g. `?!` or `."`
+        let mut end = i + c.len_utf8();
+        while let Some(&(j, c)) = chars.peek()
+            && matches!(
+                c,
+                '.' | '!' | '?' | '…' | '"' | '\'' | ')' | ']' | '”' | '’' | '»'
+            )
+        {
+            end = j + c.len_utf8();
+            chars.next();
+        }
+
+        // a sentence only ends before whitespace, which also rules out decimals like 3.14
+        if !chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+            continue;
+        }
+
+        if c == '.' && end == i + 1 && is_abbreviation(&text[start..i], &text[end..]) {
+            continue;
+        }
+
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+    sentences.push(&text[start..]);
+
+    sentences
+        .into_iter()
+        .map(str::trim)
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+/// Checks whether the period between `before` and `after` belongs to an abbreviation
+fn is_abbreviation(before: &str, after: &str) -> bool {
+    let mut words = before.split_whitespace().rev().map(trim_word);
+    let Some(word) = words.next() else {
+        return false;
+    };
+    let next = after.split_whitespace().next().map(trim_word);
+
+    // only count initials in runs, so "J. R. R. Tolkien" stays together but "Plan B. Then" doesn't
+    if is_letter(word) && (words.next().is_some_and(is_initial) || next.is_some_and(is_initial)) {
+        return true;
+    }
+
+    let word = word.to_lowercase();
+    ABBREVIATIONS.contains(&word.as_str())
+        || (NUMBERED_ABBREVIATIONS.contains(&word.as_str())
+            && next.is_some_and(|next| next.starts_with(|c: char| c.is_ascii_digit())))
+}
+
+fn trim_word(word: &str) -> &str {
+    word.trim_start_matches(|c: char| !c.is_alphanumeric())
+}
+
+fn is_letter(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars.next().is_some_and(char::is_uppercase) && chars.next().is_none()
+}
+
+fn is_initial(word: &str) -> bool {
+    word.strip_suffix('.').is_some_and(is_letter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_sentences() {
+        assert_eq!(
+            split("Hello there. How are you? I'm fine!"),
+            ["Hello there.", "How are you?", "I'm fine!"]
+        );
+    }
+
+    #[test]
+    fn no_terminator() {
+        assert_eq!(split("No terminator"), ["No terminator"]);
+        assert_eq!(split("One. And another"), ["One.", "And another"]);
+    }
+
+    #[test]
+    fn empty() {
+        assert!(split("").is_empty());
+        assert!(split(" \n ").is_empty());
+    }
+
+    #[test]
+    fn decimals() {
+        assert_eq!(
+            split("Pi is 3.14 or so. Yes."),
+            ["Pi is 3.14 or so.", "Yes."]
+        );
+    }
+
+    #[test]
+    fn abbreviations() {
+        assert_eq!(
+            split("I saw Dr. Smith today. Fruit, e.g. apples, is good."),
+            ["I saw Dr. Smith today.", "Fruit, e.g. apples, is good."]
+        );
+    }
+
+    #[test]
+    fn numbered_abbreviations() {
+        assert_eq!(split("See No. 5 here."), ["See No. 5 here."]);
+        assert_eq!(
+            split("I said no. Then I left."),
+            ["I said no.", "Then I left."]
+        );
+    }
+
+    #[test]
+    fn initials() {
+        assert_eq!(
+            split("J. R. R. Tolkien wrote it. Plan B. Then go."),
+            ["J. R. R. Tolkien wrote it.", "Plan B.", "Then go."]
+        );
+    }
+
+    #[test]
+    fn repeated_terminators_and_quotes() {
+        assert_eq!(
+            split("Really?! \"Yes.\" (Fine.) Ok."),
+            ["Really?!", "\"Yes.\"", "(Fine.)", "Ok."]
+        );
+    }
+
+    #[test]
+    fn ellipses() {
+        assert_eq!(split("Wait... what?"), ["Wait...", "what?"]);
+        assert_eq!(split("Wait… what?"), ["Wait…", "what?"]);
+    }
+
+    #[test]
+    fn non_ascii_punctuation() {
+        assert_eq!(
+            split("“Hello.” ¿Qué? «Oui.» Ja."),
+            ["“Hello.”", "¿Qué?", "«Oui.»", "Ja."]
+        );
+    }
+}